@@ -27,6 +27,10 @@ pub(crate) unsafe fn check_res(f: &str, res: *mut libc::c_char) {
 }
 
 extern "C" {
+    // `return_softmax_lse` and the now-2-slot `outp` on `mha_varlen_fwd_C`, and the new
+    // `mha_varlen_bwd_C` symbol below, assume the linked native flash-attention kernel library
+    // has been rebuilt to match; this change ships only the Rust-side FFI declarations, not the
+    // corresponding kernel sources.
     fn mha_varlen_fwd_C(
         q: *const C_tensor, // total_q x num_heads x head_size, total_q := \sum_{i=0}^{b} s_i
         k: *const C_tensor, // total_k x num_heads_k x head_size, total_k := \sum_{i=0}^{b} s_i
@@ -42,7 +46,30 @@ extern "C" {
         is_causal: bool,
         window_size_left: i32,
         window_size_right: i32,
-        outp: *mut *mut C_tensor, // total_q x num_heads x head_size, total_k := \sum_{i=0}^{b} s_i
+        return_softmax_lse: bool,
+        outp: *mut *mut C_tensor, // [out, softmax_lse]; out is total_q x num_heads x head_size,
+                                  // softmax_lse (only set if return_softmax_lse) is total_q x num_heads
+    ) -> *mut libc::c_char;
+
+    fn mha_varlen_bwd_C(
+        dout: *const C_tensor,        // total_q x num_heads x head_size
+        q: *const C_tensor,           // total_q x num_heads x head_size
+        k: *const C_tensor,           // total_k x num_heads_k x head_size
+        v: *const C_tensor,           // total_k x num_heads_k x head_size
+        out: *const C_tensor,         // total_q x num_heads x head_size, from the forward pass
+        softmax_lse: *const C_tensor, // total_q x num_heads, from the forward pass
+        cu_seqlens_q: *const C_tensor, // b+1
+        cu_seqlens_k: *const C_tensor, // b+1
+        seqused_k: *const C_tensor, // b. Must match the seqused_k passed to the forward call. (opt)
+        max_seqlen_q: i32,
+        max_seqlen_k: i32,
+        p_dropout: f32,
+        softmax_scale: f32,
+        zero_tensors: bool,
+        is_causal: bool,
+        window_size_left: i32,
+        window_size_right: i32,
+        outp: *mut *mut C_tensor, // [dq, dk, dv], same shapes as q, k, v
     ) -> *mut libc::c_char;
 }
 
@@ -61,6 +88,18 @@ extern "C" {
 /// * `seqlens_k` - The cumulative lengths of the sequences in the batch, used to index in k and v.
 /// * `max_seqlen_q` - The maximum query sequence length for q in the batch.
 /// * `max_seqlen_k` - The maximum query sequence length for k and v in the batch.
+/// * `seqused_k` - Optional per-batch-element count (shape `(batch,)`, int) of keys that are
+///   actually valid; when given, each batch element only attends to its first `seqused_k[i]`
+///   keys even though `seqlens_k` reserves more. This lets callers use a single pre-padded,
+///   block-aligned KV buffer and vary the live key count per step without rebuilding `seqlens_k`.
+/// * `window` - Optional `(left, right)` sliding-window bounds; when set, each query only
+///   attends to keys within `[pos - left, pos + right]` instead of the full context, as used
+///   by models like Mistral. Combine with `causal` and a left-only window (`right == 0`) for
+///   causal sliding-window attention. `None` keeps today's full-attention behavior.
+/// * `return_softmax_lse` - When true, also return the per-query softmax log-sum-exp (shape
+///   `(total_q, num_heads)`). Pass this along with the output to [`flash_attn_varlen_backward`]
+///   to recompute softmax during the backward pass without materializing the full attention
+///   matrix; inference-only callers can leave this `false`.
 ///
 /// `seqlens_q` and `seqlens_k` contain `batch_size + 1` elements, typically `0`, `seqlen_1`,
 /// `seqlen_1 + seqlen_2`, etc.
@@ -72,12 +111,20 @@ pub fn flash_attn_varlen(
     v: &Tensor,
     seqlens_q: &Tensor,
     seqlens_k: &Tensor,
+    seqused_k: Option<&Tensor>,
+    window: Option<(i64, i64)>,
     max_seqlen_q: usize,
     max_seqlen_k: usize,
     softmax_scale: f32,
     causal: bool,
-) -> Tensor {
-    let mut outputs = vec![std::ptr::null_mut(); 1];
+    return_softmax_lse: bool,
+) -> (Tensor, Option<Tensor>) {
+    let seqused_k = match seqused_k {
+        None => std::ptr::null(),
+        Some(t) => t.as_ptr(),
+    };
+    let (window_size_left, window_size_right) = window.unwrap_or((-1, -1));
+    let mut outputs = vec![std::ptr::null_mut(); 2];
     let err = unsafe {
         ptr_to_string(mha_varlen_fwd_C(
             q.as_ptr(),
@@ -85,26 +132,109 @@ pub fn flash_attn_varlen(
             v.as_ptr(),
             seqlens_q.as_ptr(),
             seqlens_k.as_ptr(),
-            std::ptr::null(),
+            seqused_k,
             max_seqlen_q as i32,
             max_seqlen_k as i32,
             0.0,
             softmax_scale,
             false,
             causal,
-            -1,
-            -1,
+            window_size_left as i32,
+            window_size_right as i32,
+            return_softmax_lse,
             outputs.as_mut_ptr(),
         ))
     };
     match err {
-        None => unsafe { Tensor::from_ptr(outputs[0]) },
+        None => unsafe {
+            let out = Tensor::from_ptr(outputs[0]);
+            let lse = return_softmax_lse.then(|| Tensor::from_ptr(outputs[1]));
+            (out, lse)
+        },
         Some(err) => panic!("flash_attn_varlen: {}", err),
     }
 }
 
+/// Backward pass for [`flash_attn_varlen`], enabling on-device LoRA/adapter fine-tuning.
+///
+/// Given the forward output `out`, its per-query log-sum-exp `lse` (obtained by calling
+/// [`flash_attn_varlen`] with `return_softmax_lse = true`), and the upstream gradient `dout`,
+/// recomputes `softmax(Q @ K^T . softmax_scale)` on the fly to produce `dQ`, `dK`, `dV` for the
+/// same variable-length batched layout, without ever materializing the full attention matrix.
+/// `seqused_k`, `window` and `causal` must match the forward call they correspond to, so that
+/// gradients are computed over the same restricted key range (a forward limited via
+/// `seqused_k` would otherwise get gradients over the full, partly-unused `seqlens_k` range).
+///
+/// `flash_attn_varlen` calls the FFI kernel directly rather than being registered as a `tch`
+/// custom autograd `Function`, so `Tensor::backward()` will never reach this function on its
+/// own. A fine-tuning loop must call it explicitly as the attention step of its own manual
+/// backward pass, and apply the returned `dQ`/`dK`/`dV` directly (e.g. to an optimizer step)
+/// rather than expecting them to land in `q.grad()`/`k.grad()`/`v.grad()`.
+pub fn flash_attn_varlen_backward(
+    dout: &Tensor,
+    q: &Tensor,
+    k: &Tensor,
+    v: &Tensor,
+    out: &Tensor,
+    lse: &Tensor,
+    seqlens_q: &Tensor,
+    seqlens_k: &Tensor,
+    seqused_k: Option<&Tensor>,
+    window: Option<(i64, i64)>,
+    max_seqlen_q: usize,
+    max_seqlen_k: usize,
+    softmax_scale: f32,
+    causal: bool,
+) -> (Tensor, Tensor, Tensor) {
+    let seqused_k = match seqused_k {
+        None => std::ptr::null(),
+        Some(t) => t.as_ptr(),
+    };
+    let (window_size_left, window_size_right) = window.unwrap_or((-1, -1));
+    let mut outputs = vec![std::ptr::null_mut(); 3];
+    let err = unsafe {
+        ptr_to_string(mha_varlen_bwd_C(
+            dout.as_ptr(),
+            q.as_ptr(),
+            k.as_ptr(),
+            v.as_ptr(),
+            out.as_ptr(),
+            lse.as_ptr(),
+            seqlens_q.as_ptr(),
+            seqlens_k.as_ptr(),
+            seqused_k,
+            max_seqlen_q as i32,
+            max_seqlen_k as i32,
+            0.0,
+            softmax_scale,
+            // dq/dk/dv are freshly allocated by the kernel into `outp` on every call rather
+            // than being reused scratch buffers (mirroring `flash_attn_varlen`'s forward
+            // `zero_tensors: false`), so there is nothing stale left over to zero first.
+            false,
+            causal,
+            window_size_left as i32,
+            window_size_right as i32,
+            outputs.as_mut_ptr(),
+        ))
+    };
+    match err {
+        None => unsafe {
+            (
+                Tensor::from_ptr(outputs[0]),
+                Tensor::from_ptr(outputs[1]),
+                Tensor::from_ptr(outputs[2]),
+            )
+        },
+        Some(err) => panic!("flash_attn_varlen_backward: {}", err),
+    }
+}
+
 #[allow(dead_code)]
 extern "C" {
+    // `kv_cache_dtype`/`k_scale`/`v_scale` on `paged_attention_v1_C`, `paged_attention_v2_C`
+    // and (below) `reshape_and_cache_C` are new trailing parameters added for the fp8 KV-cache
+    // mode; they assume the linked native kernel library has been rebuilt to accept them; this
+    // change ships only the Rust-side FFI declarations, not the corresponding kernel sources.
     fn paged_attention_v1_C(
         out: *mut C_tensor,
         query: *const C_tensor,
@@ -117,6 +247,9 @@ extern "C" {
         block_size: i32,
         max_context_len: i32,
         alibi_slopes: *const C_tensor,
+        kv_cache_dtype: *const libc::c_char,
+        k_scale: f32,
+        v_scale: f32,
     ) -> *mut libc::c_char;
 
     fn paged_attention_v2_C(
@@ -124,16 +257,19 @@ extern "C" {
         exp_sums: *mut C_tensor,
         max_logits: *mut C_tensor,
         tmp_out: *mut C_tensor,
-        query: *mut C_tensor,
-        key_cache: *mut C_tensor,
-        value_cache: *mut C_tensor,
+        query: *const C_tensor,
+        key_cache: *const C_tensor,
+        value_cache: *const C_tensor,
         num_kv_heads: i32,
         scale: f32,
-        block_tables: *mut C_tensor,
-        context_lens: *mut C_tensor,
+        block_tables: *const C_tensor,
+        context_lens: *const C_tensor,
         block_size: i32,
         max_context_len: i32,
         alibi_slopes: *const C_tensor,
+        kv_cache_dtype: *const libc::c_char,
+        k_scale: f32,
+        v_scale: f32,
     ) -> *mut libc::c_char;
 
     fn rms_norm_C(
@@ -171,6 +307,9 @@ extern "C" {
         key_cache: *mut C_tensor,
         value_cache: *mut C_tensor,
         slot_mapping: *const C_tensor,
+        kv_cache_dtype: *const libc::c_char,
+        k_scale: f32,
+        v_scale: f32,
     ) -> *mut libc::c_char;
 
     fn gather_cached_kv_C(
@@ -187,6 +326,37 @@ extern "C" {
         block_mapping_tensor: *const C_tensor,
         key0: *const C_tensor,
     ) -> *mut libc::c_char;
+
+    // `swap_blocks_C` is a new symbol added for CPU<->GPU KV-cache offloading; it assumes the
+    // linked native kernel library exports it with this signature, which this change does not
+    // ship (Rust-side FFI declaration only).
+    fn swap_blocks_C(
+        src: *const C_tensor,
+        dst: *mut C_tensor,
+        block_mapping: *const C_tensor,
+        stream: *mut libc::c_void,
+    ) -> *mut libc::c_char;
+}
+
+/// Selects the on-device element type used for the KV cache.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum KvCacheDtype {
+    /// Store the cache in the same dtype as the key/value tensors being written (bf16/f16).
+    #[default]
+    Auto,
+    /// Quantize to fp8 (e4m3) on write and dequantize with `k_scale`/`v_scale` on read, roughly
+    /// halving KV-cache memory and bandwidth. The block layout is unchanged; only the element
+    /// type and size shrink.
+    Fp8E4M3,
+}
+
+impl KvCacheDtype {
+    fn as_c_str(self) -> &'static str {
+        match self {
+            KvCacheDtype::Auto => "auto\0",
+            KvCacheDtype::Fp8E4M3 => "fp8_e4m3\0",
+        }
+    }
 }
 
 pub fn reshape_and_cache(
@@ -195,7 +365,15 @@ pub fn reshape_and_cache(
     key_cache: &mut Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x]
     value_cache: &mut Tensor, // [num_blocks, num_heads, head_size, block_size]
     slot_mapping: &Tensor,    // [num_tokens], int
+    kv_cache_dtype: KvCacheDtype,
+    k_scale: f32,
+    v_scale: f32,
 ) {
+    check_bf16_or_f16(key);
+    check_bf16_or_f16(value);
+    check_kv_cache_dtype(key_cache);
+    check_kv_cache_dtype(value_cache);
+
     // it's int64 in here, but int32 in gather*; go figure
     let slot_mapping = slot_mapping.to_kind(Kind::Int64);
     unsafe {
@@ -207,6 +385,9 @@ pub fn reshape_and_cache(
                 key_cache.as_mut_ptr(),
                 value_cache.as_mut_ptr(),
                 slot_mapping.as_ptr(),
+                kv_cache_dtype.as_c_str().as_ptr() as *const libc::c_char,
+                k_scale,
+                v_scale,
             ),
         );
     }
@@ -233,13 +414,46 @@ pub fn gather_cached_kv(
     }
 }
 
+/// Copies whole KV-cache blocks from `src` into `dst` according to `block_mapping` (src-block
+/// index -> dst-block index), issued asynchronously on `stream`.
+///
+/// Unlike [`copy_blocks`], which gathers across many same-device layer caches in one kernel
+/// launch, `src` and `dst` may live on different devices (e.g. GPU -> pinned CPU to evict a
+/// preempted sequence, or CPU -> GPU to page it back in), so this goes through a plain
+/// block-sized memcpy per mapped pair instead. Mirrors `copy_blocks`'s pointer-gather approach:
+/// the `(src, dst)` block index pairs are uploaded as a single device tensor rather than one
+/// call per block.
 pub fn swap_blocks(
-    _src: &Tensor,
-    _dst: &Tensor,
-    _block_mapping: &HashMap<usize, usize>,
-    _stream: &CudaStream,
+    src: &Tensor,
+    dst: &mut Tensor,
+    block_mapping: &HashMap<usize, usize>,
+    stream: &CudaStream,
 ) {
-    todo!()
+    let device = if src.device().is_cuda() {
+        src.device()
+    } else {
+        dst.device()
+    };
+    assert!(device.is_cuda(), "swap_blocks requires src or dst to be a CUDA tensor");
+
+    let mut block_mapping_vec = Vec::with_capacity(block_mapping.len() * 2);
+    for (&src_block, &dst_block) in block_mapping {
+        block_mapping_vec.push(src_block as i64);
+        block_mapping_vec.push(dst_block as i64);
+    }
+    let block_mapping_tensor = Tensor::from_slice(&block_mapping_vec).to(device);
+
+    unsafe {
+        check_res(
+            "swap_blocks_C",
+            swap_blocks_C(
+                src.as_ptr(),
+                dst.as_mut_ptr(),
+                block_mapping_tensor.as_ptr(),
+                stream.as_ptr(),
+            ),
+        );
+    }
 }
 
 fn to_cuda_ptr(t: &Tensor) -> i64 {
@@ -254,12 +468,30 @@ fn is_bf16_or_f16(t: &Tensor) -> bool {
     }
 }
 
+// An fp8 KV cache (see `KvCacheDtype::Fp8E4M3`) is stored as raw quantized bytes, one per
+// element, so it shows up here as `Uint8` rather than a float kind.
+fn is_fp8_cache(t: &Tensor) -> bool {
+    matches!(t.kind(), Kind::Uint8)
+}
+
 fn check_cont_bf16_or_f16(t: &Tensor) {
-    assert!(is_bf16_or_f16(t));
+    assert!(is_bf16_or_f16(t) || is_fp8_cache(t));
     assert!(t.device().is_cuda());
     assert!(t.is_contiguous());
 }
 
+// Query/key/value and output tensors always stay bf16/f16, even when the KV cache they read
+// from or write into is fp8-quantized; only the cache itself is allowed to be fp8.
+fn check_bf16_or_f16(t: &Tensor) {
+    assert!(is_bf16_or_f16(t));
+}
+
+// Key/value cache tensors may be plain bf16/f16 (`KvCacheDtype::Auto`) or an fp8-quantized
+// cache (`KvCacheDtype::Fp8E4M3`), dequantized on read using the kernel's `k_scale`/`v_scale`.
+fn check_kv_cache_dtype(t: &Tensor) {
+    assert!(is_bf16_or_f16(t) || is_fp8_cache(t));
+}
+
 // fn is_u32(t: &Tensor) -> bool {
 //     match t.kind() {
 //         Kind::Int => true,
@@ -353,6 +585,11 @@ pub fn rotary_embedding(
     }
 }
 
+/// Number of KV-cache tokens handled by a single CUDA block in the v2 (split-K) kernel.
+/// `max_num_partitions = ceil(max_context_len / PARTITION_SIZE)`; contexts that fit in one
+/// partition are routed to [`paged_attention_v1`] instead, see [`paged_attention`].
+const PARTITION_SIZE: usize = 512;
+
 pub fn paged_attention_v1(
     out: &mut Tensor,     // [num_seqs, num_heads, head_size]
     query: &Tensor,       // [num_seqs, num_heads, head_size]
@@ -365,7 +602,15 @@ pub fn paged_attention_v1(
     block_size: usize,
     max_context_len: usize,
     alibi_slopes: Option<&Tensor>,
+    kv_cache_dtype: KvCacheDtype,
+    k_scale: f32,
+    v_scale: f32,
 ) {
+    check_bf16_or_f16(out);
+    check_bf16_or_f16(query);
+    check_kv_cache_dtype(key_cache);
+    check_kv_cache_dtype(value_cache);
+
     let alibi_slopes = match alibi_slopes {
         None => std::ptr::null(),
         Some(t) => t.as_ptr(),
@@ -385,11 +630,139 @@ pub fn paged_attention_v1(
                 block_size as i32,
                 max_context_len as i32,
                 alibi_slopes,
+                kv_cache_dtype.as_c_str().as_ptr() as *const libc::c_char,
+                k_scale,
+                v_scale,
+            ),
+        );
+    }
+}
+
+pub fn paged_attention_v2(
+    out: &mut Tensor,     // [num_seqs, num_heads, head_size]
+    query: &Tensor,       // [num_seqs, num_heads, head_size]
+    key_cache: &Tensor,   // [num_blocks, num_heads, head_size/x, block_size, x]
+    value_cache: &Tensor, // [num_blocks, num_heads, head_size, block_size]
+    num_kv_heads: usize,
+    scale: f32,
+    block_tables: &Tensor, // [num_seqs, max_num_blocks_per_seq], int
+    context_lens: &Tensor, // [num_seqs], int
+    block_size: usize,
+    max_context_len: usize,
+    alibi_slopes: Option<&Tensor>,
+    kv_cache_dtype: KvCacheDtype,
+    k_scale: f32,
+    v_scale: f32,
+) {
+    check_bf16_or_f16(out);
+    check_bf16_or_f16(query);
+    check_kv_cache_dtype(key_cache);
+    check_kv_cache_dtype(value_cache);
+
+    let (num_seqs, num_heads, head_size) = query.size3().unwrap();
+    let max_num_partitions = max_context_len.div_ceil(PARTITION_SIZE) as i64;
+
+    // Phase 1 scratch: per-(seq, head, partition) running max/sum for the online softmax,
+    // plus the partition-local (unnormalized) output that phase 2 reduces across partitions.
+    let mut exp_sums = Tensor::empty(&[num_seqs, num_heads, max_num_partitions], (Kind::Float, query.device()));
+    let mut max_logits = Tensor::empty(&[num_seqs, num_heads, max_num_partitions], (Kind::Float, query.device()));
+    let mut tmp_out = Tensor::empty(
+        &[num_seqs, num_heads, max_num_partitions, head_size],
+        (query.kind(), query.device()),
+    );
+
+    let alibi_slopes = match alibi_slopes {
+        None => std::ptr::null(),
+        Some(t) => t.as_ptr(),
+    };
+    unsafe {
+        check_res(
+            "paged_attention_v2_C",
+            paged_attention_v2_C(
+                out.as_mut_ptr(),
+                exp_sums.as_mut_ptr(),
+                max_logits.as_mut_ptr(),
+                tmp_out.as_mut_ptr(),
+                query.as_ptr(),
+                key_cache.as_ptr(),
+                value_cache.as_ptr(),
+                num_kv_heads as i32,
+                scale,
+                block_tables.as_ptr(),
+                context_lens.as_ptr(),
+                block_size as i32,
+                max_context_len as i32,
+                alibi_slopes,
+                kv_cache_dtype.as_c_str().as_ptr() as *const libc::c_char,
+                k_scale,
+                v_scale,
             ),
         );
     }
 }
 
+/// Dispatches between the single-pass and split-K paged attention kernels.
+///
+/// Short contexts (`max_num_partitions == 1`, i.e. `max_context_len <= PARTITION_SIZE`) go
+/// to [`paged_attention_v1`], which runs one CUDA block per (seq, head) and leaves most SMs
+/// idle once there are only a few sequences. Longer contexts go to [`paged_attention_v2`],
+/// which partitions each sequence's KV blocks into `PARTITION_SIZE`-token chunks so every
+/// partition gets its own block, then reduces the per-partition results; this avoids the
+/// reduction overhead on short contexts where it wouldn't pay for itself.
+pub fn paged_attention(
+    out: &mut Tensor,
+    query: &Tensor,
+    key_cache: &Tensor,
+    value_cache: &Tensor,
+    num_kv_heads: usize,
+    scale: f32,
+    block_tables: &Tensor,
+    context_lens: &Tensor,
+    block_size: usize,
+    max_context_len: usize,
+    alibi_slopes: Option<&Tensor>,
+    kv_cache_dtype: KvCacheDtype,
+    k_scale: f32,
+    v_scale: f32,
+) {
+    let max_num_partitions = max_context_len.div_ceil(PARTITION_SIZE);
+    if max_num_partitions <= 1 {
+        paged_attention_v1(
+            out,
+            query,
+            key_cache,
+            value_cache,
+            num_kv_heads,
+            scale,
+            block_tables,
+            context_lens,
+            block_size,
+            max_context_len,
+            alibi_slopes,
+            kv_cache_dtype,
+            k_scale,
+            v_scale,
+        );
+    } else {
+        paged_attention_v2(
+            out,
+            query,
+            key_cache,
+            value_cache,
+            num_kv_heads,
+            scale,
+            block_tables,
+            context_lens,
+            block_size,
+            max_context_len,
+            alibi_slopes,
+            kv_cache_dtype,
+            k_scale,
+            v_scale,
+        );
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Default)]
 pub struct Stats {